@@ -2,7 +2,7 @@ mod common;
 use std::io::stdin;
 
 use common::*;
-use usb_gadget::function::uvc::{Frame, Uvc, UvcBuilder};
+use usb_gadget::function::uvc::{Format, Frame, Uvc, UvcBuilder};
 
 fn wait() {
     let mut buff = String::new();
@@ -14,12 +14,18 @@ fn uvc() {
     init();
 
     let mut builder = Uvc::builder();
-    builder.add_frame(&Frame {
-        format: "mjpeg",
+    builder.add_format(Format::Mjpeg {
         name: "mjpeg",
-        width: 1920,
-        height: 1080,
-        frame_intervals: vec![UvcBuilder::fps(15)]
+        frames: vec![Frame {
+            width: 1920,
+            height: 1080,
+            frame_intervals: vec![UvcBuilder::fps(15)],
+            min_bit_rate: 1920 * 1080 * 15 * 2,
+            max_bit_rate: 1920 * 1080 * 15 * 2,
+            default_frame_interval: UvcBuilder::fps(15),
+            capabilities: 0,
+            max_frame_size: 1920 * 1080 * 2,
+        }],
     });
     let (uvc, func) = builder.build();
 